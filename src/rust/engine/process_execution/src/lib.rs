@@ -0,0 +1,260 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use hashing::Digest;
+use serde::{Deserialize, Serialize};
+use workunit_store::RunningWorkunit;
+
+pub mod local;
+pub mod local_cache;
+
+#[cfg(test)]
+mod local_tests;
+#[cfg(test)]
+mod local_cache_tests;
+
+/// A location, relative to the root of a sandbox, that a `Process` reads or writes.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct RelativePath(PathBuf);
+
+impl RelativePath {
+  pub fn new<P: AsRef<std::path::Path>>(path: P) -> Result<RelativePath, String> {
+    let path = path.as_ref();
+    if path.is_absolute() {
+      return Err(format!("Expected a relative path, but got: {:?}", path));
+    }
+    if path.components().any(|c| c == std::path::Component::ParentDir) {
+      return Err(format!(
+        "Expected a path without `..` components, but got: {:?}",
+        path
+      ));
+    }
+    Ok(RelativePath(path.to_path_buf()))
+  }
+
+  pub fn to_path_buf(&self) -> PathBuf {
+    self.0.clone()
+  }
+}
+
+impl AsRef<std::path::Path> for RelativePath {
+  fn as_ref(&self) -> &std::path::Path {
+    self.0.as_ref()
+  }
+}
+
+/// The name of an append-only (mutable, unsandboxed) cache, as declared by a `Process`.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct CacheName(String);
+
+impl CacheName {
+  pub fn new(name: String) -> Result<CacheName, String> {
+    if name.is_empty() {
+      return Err("Cache names may not be empty.".to_owned());
+    }
+    Ok(CacheName(name))
+  }
+}
+
+/// Where, relative to the sandbox, an append-only cache should be made visible to a `Process`.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct CacheDest(RelativePath);
+
+impl CacheDest {
+  pub fn new(path: String) -> Result<CacheDest, String> {
+    Ok(CacheDest(RelativePath::new(path)?))
+  }
+}
+
+/// The base directory under which append-only caches are persisted between runs.
+#[derive(Clone)]
+pub struct NamedCaches {
+  base_dir: PathBuf,
+}
+
+impl NamedCaches {
+  pub fn new(base_dir: PathBuf) -> NamedCaches {
+    NamedCaches { base_dir }
+  }
+
+  pub fn local_path(&self, name: &CacheName) -> PathBuf {
+    self.base_dir.join(&name.0)
+  }
+}
+
+/// The rows/cols of a pseudo-terminal to allocate for a `Process`, for tools that only emit
+/// interactive output (color, progress bars, ...) when they detect a controlling terminal.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PtySize {
+  pub rows: u16,
+  pub cols: u16,
+}
+
+/// The platform that produced (or, as a constraint, that should produce) a `Process` result.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Platform {
+  Linux_x86_64,
+  Linux_arm64,
+  Macos_x86_64,
+  Macos_arm64,
+}
+
+impl Platform {
+  pub fn current() -> Result<Platform, String> {
+    match (std::env::consts::OS, std::env::consts::ARCH) {
+      ("linux", "x86_64") => Ok(Platform::Linux_x86_64),
+      ("linux", "aarch64") => Ok(Platform::Linux_arm64),
+      ("macos", "x86_64") => Ok(Platform::Macos_x86_64),
+      ("macos", "aarch64") => Ok(Platform::Macos_arm64),
+      (os, arch) => Err(format!("Unsupported platform: {} {}", os, arch)),
+    }
+  }
+}
+
+/// A request to run a subprocess, and capture its output.
+#[derive(Clone, Debug)]
+pub struct Process {
+  pub argv: Vec<String>,
+  pub env: BTreeMap<String, String>,
+  pub working_directory: Option<RelativePath>,
+  pub output_files: BTreeSet<RelativePath>,
+  pub output_directories: BTreeSet<RelativePath>,
+  pub input_files: Digest,
+  pub timeout: Option<Duration>,
+  /// How long to wait after sending `timeout_signal` before escalating to an unconditional
+  /// `SIGKILL`. `None` never escalates: the runner waits for `timeout_signal` to take effect,
+  /// matching the historical behavior.
+  pub timeout_grace_period: Option<Duration>,
+  /// The signal sent to the process group when `timeout` elapses, before any escalation to
+  /// `SIGKILL`. Defaults to `SIGTERM`; tools that want a chance to flush state on a different
+  /// signal (e.g. `SIGINT`) can override it.
+  pub timeout_signal: i32,
+  pub description: String,
+  pub jdk_home: Option<PathBuf>,
+  pub append_only_caches: BTreeMap<CacheName, CacheDest>,
+  /// Bytes to write to the child's stdin before closing it. `None` means stdin is closed
+  /// immediately without writing anything, rather than being inherited from the parent.
+  pub stdin_digest: Option<Digest>,
+  /// When set, the child is given a pseudo-terminal of this size instead of plain pipes.
+  pub pty: Option<PtySize>,
+}
+
+impl Process {
+  pub fn new(argv: Vec<String>) -> Process {
+    Process {
+      argv,
+      env: BTreeMap::new(),
+      working_directory: None,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      input_files: hashing::EMPTY_DIGEST,
+      timeout: None,
+      timeout_grace_period: None,
+      timeout_signal: libc::SIGTERM,
+      description: String::new(),
+      jdk_home: None,
+      append_only_caches: BTreeMap::new(),
+      stdin_digest: None,
+      pty: None,
+    }
+  }
+
+  pub fn env(mut self, env: BTreeMap<String, String>) -> Process {
+    self.env = env;
+    self
+  }
+
+  pub fn output_files(mut self, output_files: BTreeSet<RelativePath>) -> Process {
+    self.output_files = output_files;
+    self
+  }
+
+  pub fn output_directories(mut self, output_directories: BTreeSet<RelativePath>) -> Process {
+    self.output_directories = output_directories;
+    self
+  }
+
+  pub fn append_only_caches(
+    mut self,
+    append_only_caches: BTreeMap<CacheName, CacheDest>,
+  ) -> Process {
+    self.append_only_caches = append_only_caches;
+    self
+  }
+
+  pub fn stdin_digest(mut self, stdin_digest: Option<Digest>) -> Process {
+    self.stdin_digest = stdin_digest;
+    self
+  }
+
+  pub fn pty(mut self, pty: Option<PtySize>) -> Process {
+    self.pty = pty;
+    self
+  }
+
+  pub fn timeout_signal(mut self, timeout_signal: i32) -> Process {
+    self.timeout_signal = timeout_signal;
+    self
+  }
+}
+
+/// A `Process`, indexed by the `Platform`(s) it is valid to run it on. For the local runner a
+/// single `Process` is always relevant, so this is kept as a thin wrapper rather than the full
+/// multi-platform map that remote execution strategies key off of.
+#[derive(Clone, Debug)]
+pub struct MultiPlatformProcess(pub Process);
+
+impl From<Process> for MultiPlatformProcess {
+  fn from(process: Process) -> MultiPlatformProcess {
+    MultiPlatformProcess(process)
+  }
+}
+
+/// Metadata about how a `Process` was executed, beyond its output.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProcessResultMetadata {
+  /// The signal number (e.g. `libc::SIGTERM`, as a positive value) that ultimately terminated
+  /// the process on timeout, if any. Distinguishes a graceful shutdown (the soft signal) from a
+  /// forced kill (escalation to `SIGKILL` after the grace period elapsed).
+  pub terminating_signal: Option<i32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FallibleProcessResultWithPlatform {
+  pub stdout_digest: Digest,
+  pub stderr_digest: Digest,
+  pub exit_code: i32,
+  pub output_directory: Digest,
+  pub platform: Platform,
+  pub metadata: ProcessResultMetadata,
+}
+
+impl PartialEq for FallibleProcessResultWithPlatform {
+  fn eq(&self, other: &Self) -> bool {
+    self.stdout_digest == other.stdout_digest
+      && self.stderr_digest == other.stderr_digest
+      && self.exit_code == other.exit_code
+      && self.output_directory == other.output_directory
+      && self.platform == other.platform
+      && self.metadata == other.metadata
+  }
+}
+
+/// A context carried through a build graph execution, identifying e.g. which run a request
+/// belongs to. Kept minimal here: the local runner does not currently branch on its contents.
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+  pub build_id: String,
+}
+
+#[async_trait]
+pub trait CommandRunner: Send + Sync {
+  async fn run(
+    &self,
+    context: Context,
+    workunit: &mut RunningWorkunit,
+    req: MultiPlatformProcess,
+  ) -> Result<FallibleProcessResultWithPlatform, String>;
+}
@@ -0,0 +1,713 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use store::Store;
+use tempfile::TempDir;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use workunit_store::RunningWorkunit;
+
+use crate::{
+  CommandRunner as CommandRunnerTrait, Context, FallibleProcessResultWithPlatform,
+  MultiPlatformProcess, NamedCaches, Platform, Process, ProcessResultMetadata, PtySize,
+  RelativePath,
+};
+
+/// The ASCII EOT ("end of transmission") byte, conventionally bound to a pty's `VEOF` control
+/// character (it's what a terminal sends when the user presses Ctrl-D). Writing it to a pty's
+/// master side is how we signal stdin EOF to a child reading from the slave side -- see its use
+/// in `CommandRunner::run` for why a plain fd close doesn't work here.
+const PTY_EOF: u8 = 4;
+
+/// Runs `Process`es locally, by materializing their input files, spawning a subprocess, and
+/// capturing its output.
+#[derive(Clone)]
+pub struct CommandRunner {
+  store: Store,
+  executor: task_executor::Executor,
+  work_dir_base: PathBuf,
+  named_caches: NamedCaches,
+  cleanup_local_dirs: bool,
+}
+
+impl CommandRunner {
+  pub fn new(
+    store: Store,
+    executor: task_executor::Executor,
+    work_dir_base: PathBuf,
+    named_caches: NamedCaches,
+    cleanup_local_dirs: bool,
+  ) -> CommandRunner {
+    CommandRunner {
+      store,
+      executor,
+      work_dir_base,
+      named_caches,
+      cleanup_local_dirs,
+    }
+  }
+
+  async fn materialize_inputs(&self, workdir: &Path, process: &Process) -> Result<(), String> {
+    if process.input_files != hashing::EMPTY_DIGEST {
+      self
+        .store
+        .materialize_directory(workdir.to_path_buf(), process.input_files)
+        .await?;
+    }
+    for (name, dest) in &process.append_only_caches {
+      let cache_dir = self.named_caches.local_path(name);
+      std::fs::create_dir_all(&cache_dir)
+        .map_err(|e| format!("Failed to create append-only cache dir: {}", e))?;
+      let link_path = workdir.join(dest.0.to_path_buf());
+      if let Some(parent) = link_path.parent() {
+        std::fs::create_dir_all(parent)
+          .map_err(|e| format!("Failed to create cache link parent: {}", e))?;
+      }
+      #[cfg(unix)]
+      std::os::unix::fs::symlink(&cache_dir, &link_path).map_err(|e| {
+        format!(
+          "Failed to symlink named cache {:?} -> {:?}: {}",
+          link_path, cache_dir, e
+        )
+      })?;
+    }
+    if let Some(jdk_home) = &process.jdk_home {
+      #[cfg(unix)]
+      std::os::unix::fs::symlink(jdk_home, workdir.join(".jdk"))
+        .map_err(|e| format!("Failed to symlink .jdk: {}", e))?;
+    }
+    Ok(())
+  }
+
+  fn create_output_parent_dirs(
+    cwd: &Path,
+    output_files: &BTreeSet<RelativePath>,
+    output_directories: &BTreeSet<RelativePath>,
+  ) -> Result<(), String> {
+    for output in output_files.iter().chain(output_directories.iter()) {
+      if let Some(parent) = output.to_path_buf().parent() {
+        std::fs::create_dir_all(cwd.join(parent))
+          .map_err(|e| format!("Failed to create output parent dir: {}", e))?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Writes `contents` to a sibling temp file in `dest`'s parent directory and renames it into
+  /// place, so that a preserved sandbox inspected by a human or other tooling mid-run never
+  /// observes a partially written file. Preserves `dest`'s existing permission bits (if any),
+  /// since `tempfile_in` creates the temp file with a restrictive owner-only mode and the rename
+  /// would otherwise silently strip e.g. the executable bit off of compiled binaries or scripts.
+  fn write_atomically(dest: &Path, contents: &[u8]) -> Result<(), String> {
+    let parent = dest
+      .parent()
+      .ok_or_else(|| format!("{:?} has no parent directory", dest))?;
+    let original_permissions = std::fs::metadata(dest).ok().map(|m| m.permissions());
+    let mut temp_file = tempfile::Builder::new()
+      .prefix(".tmp-")
+      .tempfile_in(parent)
+      .map_err(|e| format!("Failed to create temp file in {:?}: {}", parent, e))?;
+    std::io::Write::write_all(&mut temp_file, contents)
+      .map_err(|e| format!("Failed to write {:?}: {}", dest, e))?;
+    if let Some(permissions) = original_permissions {
+      temp_file
+        .as_file()
+        .set_permissions(permissions)
+        .map_err(|e| format!("Failed to preserve permissions on {:?}: {}", dest, e))?;
+    }
+    temp_file
+      .persist(dest)
+      .map_err(|e| format!("Failed to materialize {:?}: {}", dest, e))?;
+    Ok(())
+  }
+
+  /// Recursively re-materializes every regular file under `dir` via [`write_atomically`], for the
+  /// same reason individual `output_files` and `__run.sh` are: an output *directory* (the more
+  /// common case for e.g. compilers) is written into directly by the child, so without this its
+  /// contents would still be exposed to a concurrent reader mid-write.
+  fn rematerialize_directory_atomically(dir: &Path) -> Result<(), String> {
+    let entries = match std::fs::read_dir(dir) {
+      Ok(entries) => entries,
+      Err(_) => return Ok(()),
+    };
+    for entry in entries {
+      let entry = entry.map_err(|e| format!("Failed to read directory entry in {:?}: {}", dir, e))?;
+      let path = entry.path();
+      let file_type = entry
+        .file_type()
+        .map_err(|e| format!("Failed to stat {:?}: {}", path, e))?;
+      if file_type.is_dir() {
+        Self::rematerialize_directory_atomically(&path)?;
+      } else if file_type.is_file() {
+        if let Ok(contents) = std::fs::read(&path) {
+          Self::write_atomically(&path, &contents)?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn write_run_script(workdir: &Path, process: &Process) -> Result<(), String> {
+    let mut script = String::new();
+    script.push_str("#!/bin/bash\n");
+    if let Some(working_directory) = &process.working_directory {
+      script.push_str(&format!(
+        "cd \"$(dirname \"$0\")/{}\"\n",
+        working_directory.to_path_buf().display()
+      ));
+    } else {
+      script.push_str("cd \"$(dirname \"$0\")\"\n");
+    }
+    for (key, value) in &process.env {
+      let quoted_value = String::from_utf8(shell_quote::bash::escape(value))
+        .unwrap_or_else(|_| value.clone());
+      script.push_str(&format!("export {}={}\n", key, quoted_value));
+    }
+    let quoted_command_line = String::from_utf8(shell_quote::bash::escape(&process.argv.join(" ")))
+      .unwrap_or_else(|_| process.argv.join(" "));
+    script.push_str(&quoted_command_line);
+    script.push('\n');
+
+    let run_script_path = workdir.join("__run.sh");
+    Self::write_atomically(&run_script_path, script.as_bytes())?;
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      let mut perms = std::fs::metadata(&run_script_path)
+        .map_err(|e| format!("Failed to stat __run.sh: {}", e))?
+        .permissions();
+      perms.set_mode(0o755);
+      std::fs::set_permissions(&run_script_path, perms)
+        .map_err(|e| format!("Failed to make __run.sh executable: {}", e))?;
+    }
+    Ok(())
+  }
+
+  /// Surfaces how much output has been captured so far on the given workunit, so that a
+  /// long-running process which hasn't exited yet still shows progress rather than going silent
+  /// until it completes.
+  fn report_output_progress(workunit: &mut RunningWorkunit, stdout_len: usize, stderr_len: usize) {
+    workunit.update_metadata(|metadata| {
+      let mut metadata = metadata.unwrap_or_default();
+      metadata.message = Some(format!(
+        "captured {} bytes of stdout, {} bytes of stderr so far",
+        stdout_len, stderr_len
+      ));
+      Some(metadata)
+    });
+  }
+
+  /// Writes one chunk of `bytes` (starting at `*offset`) to `stdin`, so that it can be raced
+  /// against stdout/stderr draining in a `tokio::select!` loop instead of writing to completion
+  /// up front. Closes (and clears) `stdin` once it's all been written.
+  async fn write_stdin_chunk(
+    stdin: &mut Option<tokio::process::ChildStdin>,
+    bytes: &[u8],
+    offset: &mut usize,
+  ) -> Result<(), String> {
+    let handle = stdin
+      .as_mut()
+      .expect("write_stdin_chunk called with no stdin handle");
+    let n = handle
+      .write(&bytes[*offset..])
+      .await
+      .map_err(|e| format!("Failed to write stdin: {}", e))?;
+    *offset += n;
+    if *offset >= bytes.len() {
+      // Dropping the handle closes the write end, signalling EOF to the child.
+      stdin.take();
+    }
+    Ok(())
+  }
+
+  /// Snapshots the process's declared `output_files`/`output_directories` out of `cwd` into the
+  /// `Store`, returning `EMPTY_DIGEST` when neither was requested so that a process with no
+  /// declared outputs doesn't pay for a directory walk.
+  async fn snapshot_outputs(&self, cwd: &Path, process: &Process) -> Result<hashing::Digest, String> {
+    if process.output_files.is_empty() && process.output_directories.is_empty() {
+      return Ok(hashing::EMPTY_DIGEST);
+    }
+    let mut output_globs: Vec<String> = process
+      .output_files
+      .iter()
+      .map(|relative_path| relative_path.to_path_buf().to_string_lossy().into_owned())
+      .collect();
+    output_globs.extend(process.output_directories.iter().map(|relative_path| {
+      format!(
+        "{}/**",
+        relative_path.to_path_buf().to_string_lossy().into_owned()
+      )
+    }));
+    let snapshot = self
+      .store
+      .snapshot_of_one_directory(cwd.to_path_buf(), output_globs, true)
+      .await?;
+    Ok(snapshot.digest)
+  }
+
+  /// Allocates a pseudo-terminal via the standard POSIX `posix_openpt`/`grantpt`/`unlockpt`
+  /// sequence, returning the master and slave fds. The caller is responsible for making the
+  /// slave the child's controlling terminal and dup'ing it onto stdin/stdout/stderr.
+  #[cfg(unix)]
+  fn open_pty(size: PtySize) -> Result<(std::fs::File, std::fs::File), String> {
+    use std::ffi::CStr;
+    use std::os::unix::io::FromRawFd;
+
+    unsafe {
+      let master_fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+      if master_fd < 0 {
+        return Err("posix_openpt failed".to_owned());
+      }
+      if libc::grantpt(master_fd) != 0 {
+        return Err("grantpt failed".to_owned());
+      }
+      if libc::unlockpt(master_fd) != 0 {
+        return Err("unlockpt failed".to_owned());
+      }
+      let mut name_buf = [0i8; 128];
+      if libc::ptsname_r(master_fd, name_buf.as_mut_ptr(), name_buf.len()) != 0 {
+        return Err("ptsname_r failed".to_owned());
+      }
+      let slave_fd = libc::open(name_buf.as_ptr(), libc::O_RDWR | libc::O_NOCTTY);
+      if slave_fd < 0 {
+        let slave_name = CStr::from_ptr(name_buf.as_ptr()).to_string_lossy();
+        return Err(format!("Failed to open pty slave {}", slave_name));
+      }
+      let ws = libc::winsize {
+        ws_row: size.rows,
+        ws_col: size.cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+      };
+      if libc::ioctl(slave_fd, libc::TIOCSWINSZ, &ws) != 0 {
+        return Err("Failed to set pty window size".to_owned());
+      }
+      Ok((
+        std::fs::File::from_raw_fd(master_fd),
+        std::fs::File::from_raw_fd(slave_fd),
+      ))
+    }
+  }
+}
+
+#[async_trait]
+impl CommandRunnerTrait for CommandRunner {
+  async fn run(
+    &self,
+    _context: Context,
+    workunit: &mut RunningWorkunit,
+    req: MultiPlatformProcess,
+  ) -> Result<FallibleProcessResultWithPlatform, String> {
+    let process = req.0;
+
+    let workdir_handle = if self.cleanup_local_dirs {
+      Some(
+        TempDir::new_in(&self.work_dir_base)
+          .map_err(|e| format!("Failed to make tempdir for local execution: {}", e))?,
+      )
+    } else {
+      None
+    };
+    let workdir = match &workdir_handle {
+      Some(handle) => handle.path().to_path_buf(),
+      None => {
+        let dir = tempfile::Builder::new()
+          .prefix("process-execution")
+          .tempdir_in(&self.work_dir_base)
+          .map_err(|e| format!("Failed to make preserved workdir: {}", e))?
+          .into_path();
+        dir
+      }
+    };
+
+    self.materialize_inputs(&workdir, &process).await?;
+
+    let cwd = match &process.working_directory {
+      Some(rel) => workdir.join(rel.to_path_buf()),
+      None => workdir.clone(),
+    };
+    Self::create_output_parent_dirs(&cwd, &process.output_files, &process.output_directories)?;
+
+    if !self.cleanup_local_dirs {
+      Self::write_run_script(&workdir, &process)?;
+    }
+
+    let mut command = Command::new(&process.argv[0]);
+    command
+      .args(&process.argv[1..])
+      .current_dir(&cwd)
+      .env_clear()
+      .envs(&process.env)
+      .kill_on_drop(true);
+    // Make the child the leader of a new process group, so that on timeout we can signal the
+    // whole group (and thereby reap orphaned grandchildren it spawned) rather than just it.
+    #[cfg(unix)]
+    unsafe {
+      command.pre_exec(|| {
+        if libc::setsid() < 0 {
+          return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+      });
+    }
+
+    // Kept alive until after `command.spawn()` returns: `slave_fd` is captured by raw fd number
+    // into the `pre_exec` closure below, and dropping `slave` (closing that fd) before `fork()`
+    // happens would leave the closure's `TIOCSCTTY` ioctl operating on a dead fd, failing spawn
+    // with EBADF.
+    let mut pty_slave = None;
+    let pty_master = if let Some(size) = process.pty {
+      #[cfg(unix)]
+      {
+        use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+        let (master, slave) = Self::open_pty(size)?;
+        let slave_fd: RawFd = slave.as_raw_fd();
+        command.stdin(unsafe { Stdio::from_raw_fd(libc::dup(slave_fd)) });
+        command.stdout(unsafe { Stdio::from_raw_fd(libc::dup(slave_fd)) });
+        command.stderr(unsafe { Stdio::from_raw_fd(libc::dup(slave_fd)) });
+        unsafe {
+          // Make the slave the child's controlling terminal, now that `setsid` above has
+          // detached it from pants' own controlling terminal (a prerequisite for `TIOCSCTTY`).
+          command.pre_exec(move || {
+            if libc::ioctl(slave_fd, libc::TIOCSCTTY as _, 0) != 0 {
+              return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+          });
+        }
+        pty_slave = Some(slave);
+        Some(master)
+      }
+      #[cfg(not(unix))]
+      {
+        return Err("pty execution is only supported on unix".to_owned());
+      }
+    } else {
+      command
+        .stdin(if process.stdin_digest.is_some() {
+          Stdio::piped()
+        } else {
+          Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+      None
+    };
+
+    let mut child = command
+      .spawn()
+      .map_err(|e| format!("Failed to execute: {:?}: {}", process.argv, e))?;
+    // The child has its own dup'd fds onto the slave now; our copy (and the fd `pre_exec`
+    // referenced) is no longer needed.
+    drop(pty_slave);
+    let child_pid = child.id().ok_or_else(|| "Child has no pid".to_owned())?;
+    // Measured from spawn, so that time spent draining output counts against `timeout`.
+    let timeout_deadline = process
+      .timeout
+      .map(|timeout| tokio::time::Instant::now() + timeout);
+
+    let stdin_bytes = match process.stdin_digest {
+      Some(digest) => Some(
+        self
+          .store
+          .load_file_bytes_with(digest, |b| b.to_vec())
+          .await?
+          .ok_or_else(|| format!("Stdin contents not found in store: {:?}", digest))?
+          .0,
+      ),
+      None => None,
+    };
+
+    let mut stdout_bytes = Vec::new();
+    let mut stderr_bytes = Vec::new();
+    let mut _held_pipes = None;
+    if let Some(master) = pty_master {
+      // The pty master fd is bidirectional and blocking, so unlike the plain-pipes path below
+      // (which uses `tokio::io::AsyncReadExt`), both the read and write sides run via
+      // `spawn_blocking` on the dedicated blocking-pool thread rather than the tokio worker.
+      // Always clone the master so we can write the EOF byte below, even with no stdin requested:
+      // as with the plain-pipes path's immediate stdin close in that case, a pty child reading to
+      // EOF needs to see one regardless of whether we send it any bytes.
+      let mut write_master = Some(
+        master
+          .try_clone()
+          .map_err(|e| format!("Failed to clone pty master fd: {}", e))?,
+      );
+      let write_task = tokio::task::spawn_blocking(move || {
+        let handle = write_master.as_mut().unwrap();
+        let mut at_line_start = true;
+        if let Some(bytes) = stdin_bytes {
+          std::io::Write::write_all(handle, &bytes)
+            .map_err(|e| format!("Failed to write stdin to pty: {}", e))?;
+          at_line_start = bytes.is_empty() || bytes.ends_with(b"\n");
+        }
+        // Closing this fd wouldn't signal EOF here (the slave only hangs up once *every*
+        // master-side fd is closed, and `read_master` below stays open); the VEOF control byte
+        // is what makes the terminal driver return EOF from the child's `read()` instead. A VEOF
+        // after a partial line only hands that line to the reader, so a second one follows it.
+        let eof: &[u8] = if at_line_start {
+          &[PTY_EOF]
+        } else {
+          &[PTY_EOF, PTY_EOF]
+        };
+        std::io::Write::write_all(handle, eof)
+          .map_err(|e| format!("Failed to write stdin EOF to pty: {}", e))
+      });
+
+      let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
+      let mut read_master = master;
+      let read_task = tokio::task::spawn_blocking(move || {
+        use std::io::Read;
+        // The pty master fd carries the combined stdout+stderr stream; read it until the slave
+        // side is closed (i.e. the child, and any children it spawned, have all exited).
+        let mut buf = [0u8; 4096];
+        loop {
+          match read_master.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+              if tx.send(buf[..n].to_vec()).is_err() {
+                break;
+              }
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            // A pty master read fails with EIO once every slave fd has been closed.
+            Err(ref e) if e.raw_os_error() == Some(libc::EIO) => break,
+            Err(e) => return Err(format!("Failed to read from pty: {}", e)),
+          }
+        }
+        Ok(())
+      });
+
+      while let Some(chunk) = rx.recv().await {
+        stdout_bytes.extend_from_slice(&chunk);
+        Self::report_output_progress(workunit, stdout_bytes.len(), 0);
+      }
+      read_task
+        .await
+        .map_err(|e| format!("pty read task panicked: {}", e))??;
+      write_task
+        .await
+        .map_err(|e| format!("pty write task panicked: {}", e))??;
+    } else {
+      let mut stdout_pipe = child.stdout.take().expect("stdout was requested as piped");
+      let mut stderr_pipe = child.stderr.take().expect("stderr was requested as piped");
+      use tokio::io::AsyncReadExt;
+
+      let mut stdin_pipe = child.stdin.take();
+      let mut stdin_offset = 0usize;
+      if stdin_bytes.is_none() {
+        // No stdin was requested: the child's stdin is the null device (see `Stdio::null()`
+        // above), so tools which block waiting for EOF on stdin see it immediately and don't hang.
+        drop(stdin_pipe.take());
+      }
+
+      // Drain stdout/stderr and write stdin all concurrently, rather than writing the entirety of
+      // stdin to completion before reading any output (or vice versa): a child that only reads
+      // part of a large stdin payload before writing enough output to fill the stdout/stderr
+      // pipes would otherwise deadlock against us, since we'd be blocked writing the rest of
+      // stdin while the child is blocked writing output we haven't started draining.
+      let mut stdout_open = true;
+      let mut stderr_open = true;
+      let mut stdout_chunk = [0u8; 8192];
+      let mut stderr_chunk = [0u8; 8192];
+      while stdout_open || stderr_open || stdin_pipe.is_some() {
+        tokio::select! {
+          result = stdout_pipe.read(&mut stdout_chunk), if stdout_open => {
+            match result.map_err(|e| format!("Failed to read stdout: {}", e))? {
+              0 => stdout_open = false,
+              n => {
+                stdout_bytes.extend_from_slice(&stdout_chunk[..n]);
+                Self::report_output_progress(workunit, stdout_bytes.len(), stderr_bytes.len());
+              }
+            }
+          },
+          result = stderr_pipe.read(&mut stderr_chunk), if stderr_open => {
+            match result.map_err(|e| format!("Failed to read stderr: {}", e))? {
+              0 => stderr_open = false,
+              n => {
+                stderr_bytes.extend_from_slice(&stderr_chunk[..n]);
+                Self::report_output_progress(workunit, stdout_bytes.len(), stderr_bytes.len());
+              }
+            }
+          },
+          result = Self::write_stdin_chunk(&mut stdin_pipe, stdin_bytes.as_deref().unwrap_or(&[]), &mut stdin_offset), if stdin_pipe.is_some() => {
+            result?;
+          },
+          _ = tokio::time::sleep_until(timeout_deadline.unwrap_or_else(tokio::time::Instant::now)), if timeout_deadline.is_some() => break,
+        }
+      }
+      // Held open until the child has been waited on below, so that a child still running when
+      // `timeout` elapses isn't ended early by SIGPIPE on its output pipes instead.
+      _held_pipes = Some((stdout_pipe, stderr_pipe));
+    }
+
+    let mut terminating_signal = None;
+    let exit_status = if let (Some(timeout), Some(deadline)) = (process.timeout, timeout_deadline) {
+      match tokio::time::timeout_at(deadline, child.wait()).await {
+        Ok(status) => status.map_err(|e| format!("Failed to wait for child: {}", e))?,
+        Err(_) => {
+          let signal = Self::kill_with_grace_period(
+            &mut child,
+            child_pid,
+            process.timeout_signal,
+            process.timeout_grace_period,
+          )
+          .await?;
+          terminating_signal = Some(signal);
+          stdout_bytes.extend_from_slice(
+            format!(
+              "\n\nExceeded timeout of {:?} for {}; terminated with {}\n",
+              timeout,
+              process.description,
+              Self::signal_name(signal)
+            )
+            .as_bytes(),
+          );
+          child
+            .wait()
+            .await
+            .map_err(|e| format!("Failed to wait for child: {}", e))?
+        }
+      }
+    } else {
+      child
+        .wait()
+        .await
+        .map_err(|e| format!("Failed to wait for child: {}", e))?
+    };
+
+    if !self.cleanup_local_dirs {
+      // The child already wrote these files/directories directly; re-materialize each one via
+      // write-then-rename so that a preserved sandbox never exposes a file mid-write to a
+      // concurrent reader (the child's own write is not atomic from our perspective).
+      for output_file in &process.output_files {
+        let path = cwd.join(output_file.to_path_buf());
+        if let Ok(contents) = std::fs::read(&path) {
+          Self::write_atomically(&path, &contents)?;
+        }
+      }
+      for output_directory in &process.output_directories {
+        Self::rematerialize_directory_atomically(&cwd.join(output_directory.to_path_buf()))?;
+      }
+    }
+
+    #[cfg(unix)]
+    let exit_code = {
+      use std::os::unix::process::ExitStatusExt;
+      exit_status
+        .code()
+        .unwrap_or_else(|| -exit_status.signal().unwrap_or(0))
+    };
+    #[cfg(not(unix))]
+    let exit_code = exit_status.code().unwrap_or(-1);
+
+    let output_directory = self.snapshot_outputs(&cwd, &process).await?;
+    let stdout_digest = self.store.store_file_bytes(stdout_bytes, false).await?;
+    let stderr_digest = self.store.store_file_bytes(stderr_bytes, false).await?;
+
+    Ok(FallibleProcessResultWithPlatform {
+      stdout_digest,
+      stderr_digest,
+      exit_code,
+      output_directory,
+      platform: Platform::current()?,
+      metadata: ProcessResultMetadata {
+        terminating_signal,
+      },
+    })
+  }
+}
+
+impl CommandRunner {
+  /// Maps a raw signal number to its conventional name, for inclusion in the timeout message;
+  /// falls back to the raw number for signals we don't special-case.
+  #[cfg(unix)]
+  fn signal_name(signal: i32) -> String {
+    match signal {
+      libc::SIGTERM => "SIGTERM".to_owned(),
+      libc::SIGKILL => "SIGKILL".to_owned(),
+      libc::SIGINT => "SIGINT".to_owned(),
+      libc::SIGHUP => "SIGHUP".to_owned(),
+      libc::SIGQUIT => "SIGQUIT".to_owned(),
+      _ => format!("signal {}", signal),
+    }
+  }
+
+  #[cfg(not(unix))]
+  fn signal_name(_signal: i32) -> String {
+    "the timeout signal".to_owned()
+  }
+
+  /// Sends `signal` to the whole process group (so that orphaned grandchildren the child
+  /// spawned are signaled too, not just the direct child), then waits up to `grace_period` for
+  /// the group to exit before escalating to an unconditional `SIGKILL`. With no grace period,
+  /// waits indefinitely for the soft signal to take effect instead of escalating, matching the
+  /// historical (pre-grace-period) behavior of a single signal followed by an unbounded `wait()`.
+  /// Returns whichever signal actually caused the child to exit.
+  #[cfg(unix)]
+  async fn kill_with_grace_period(
+    child: &mut tokio::process::Child,
+    child_pid: u32,
+    signal: i32,
+    grace_period: Option<Duration>,
+  ) -> Result<i32, String> {
+    let pgid = -(child_pid as i32);
+    unsafe {
+      libc::kill(pgid, signal);
+    }
+
+    let grace_period = match grace_period {
+      Some(grace_period) => grace_period,
+      None => {
+        child
+          .wait()
+          .await
+          .map_err(|e| format!("Failed to wait for child: {}", e))?;
+        return Ok(signal);
+      }
+    };
+    let deadline = Instant::now() + grace_period;
+    loop {
+      match child.try_wait() {
+        Ok(Some(_)) => return Ok(signal),
+        Ok(None) => {}
+        Err(e) => return Err(format!("Failed to poll child during grace period: {}", e)),
+      }
+      if Instant::now() >= deadline {
+        break;
+      }
+      tokio::time::sleep(Duration::from_millis(20).min(deadline - Instant::now())).await;
+    }
+
+    match child.try_wait() {
+      Ok(Some(_)) => Ok(signal),
+      _ => {
+        unsafe {
+          libc::kill(pgid, libc::SIGKILL);
+        }
+        Ok(libc::SIGKILL)
+      }
+    }
+  }
+
+  /// Non-unix fallback: there's no process-group signal to send, so this can only ever kill the
+  /// direct child (orphaned grandchildren it spawned are not reaped) and has no soft-signal/grace
+  /// period distinction to report back.
+  #[cfg(not(unix))]
+  async fn kill_with_grace_period(
+    child: &mut tokio::process::Child,
+    _child_pid: u32,
+    _signal: i32,
+    _grace_period: Option<Duration>,
+  ) -> Result<i32, String> {
+    child
+      .kill()
+      .await
+      .map_err(|e| format!("Failed to kill child: {}", e))?;
+    Ok(0)
+  }
+}
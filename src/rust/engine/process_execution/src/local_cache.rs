@@ -0,0 +1,255 @@
+// A `CommandRunner` decorator that short-circuits re-execution of a `Process` by keeping a
+// TTL-bounded cache of results on local disk, next to the `NamedCaches` directory.
+//
+// Unlike the remote action cache, this cache is keyed purely on a fingerprint of the request, and
+// entries expire after a configurable freshness window rather than living forever. This makes it
+// suitable for caching the results of processes whose correctness depends on wall-clock time or
+// on external state pants doesn't model (for example: linters invoked against a mutable `PATH`).
+//
+// This cache has no relationship to the `Store`'s own GC, so a hit's output digests are checked
+// for presence before being returned; a hit whose digests were collected out from under it is
+// treated as a miss.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use hashing::{Digest, Fingerprint};
+use serde::{Deserialize, Serialize};
+use store::Store;
+use workunit_store::{Level, RunningWorkunit};
+
+use crate::{
+  CommandRunner as CommandRunnerTrait, Context, FallibleProcessResultWithPlatform,
+  MultiPlatformProcess,
+};
+
+/// How fresh a cache entry must be to be returned without re-running the process.
+#[derive(Clone, Copy, Debug)]
+pub struct CacheTTL {
+  /// Entries younger than this are returned directly.
+  pub fresh: Duration,
+  /// Entries older than `fresh` but younger than `stale` are returned immediately, while a
+  /// refresh of the entry is kicked off in the background. Entries older than `stale` (or when
+  /// `stale` is `None`) force a synchronous re-run.
+  pub stale: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+  completed_at_millis_since_epoch: u64,
+  result: FallibleProcessResultWithPlatform,
+}
+
+/// A `CommandRunner` decorator that caches the results of its inner runner on local disk for a
+/// configurable amount of time.
+#[derive(Clone)]
+pub struct CommandRunner {
+  store: Store,
+  inner: Arc<dyn CommandRunnerTrait>,
+  executor: task_executor::Executor,
+  cache_dir: PathBuf,
+  ttl: CacheTTL,
+  // Wall-clock timestamps only have millisecond resolution, so two writes of the same
+  // fingerprint's entry within one process can land in the same millisecond and make a
+  // `fresh: Duration::from_millis(0)` entry look spuriously fresh when read back immediately.
+  // Track the `Instant` of each entry this process itself wrote, and prefer that (guaranteed
+  // monotonic and effectively never tied, since real work happens between any two writes) over
+  // the persisted millisecond timestamp when it's available.
+  write_instants: Arc<Mutex<HashMap<Fingerprint, Instant>>>,
+  // Fingerprints with a stale-while-refresh background task currently outstanding. Without this,
+  // concurrent (or closely-spaced) requests for the same stale fingerprint would each spawn their
+  // own redundant refresh before the first one lands in `write_instants`, defeating the point of
+  // caching for anything but a single caller.
+  refreshing: Arc<Mutex<HashSet<Fingerprint>>>,
+}
+
+impl CommandRunner {
+  pub fn new(
+    store: Store,
+    inner: Arc<dyn CommandRunnerTrait>,
+    executor: task_executor::Executor,
+    cache_dir: PathBuf,
+    ttl: CacheTTL,
+  ) -> CommandRunner {
+    CommandRunner {
+      store,
+      inner,
+      executor,
+      cache_dir,
+      ttl,
+      write_instants: Arc::new(Mutex::new(HashMap::new())),
+      refreshing: Arc::new(Mutex::new(HashSet::new())),
+    }
+  }
+
+  /// Whether every digest referenced by `result` is still present in the `Store`. This cache has
+  /// no relationship to the `Store`'s own GC, and entries can be served for up to `ttl.stale`, so
+  /// a hit can otherwise hand back digests that have since been collected -- surfacing as a
+  /// confusing load failure well after the fact in whichever unrelated code next reads them.
+  async fn result_digests_exist(&self, result: &FallibleProcessResultWithPlatform) -> bool {
+    for digest in [result.stdout_digest, result.stderr_digest] {
+      if !matches!(self.store.load_file_bytes_with(digest, |_| ()).await, Ok(Some(_))) {
+        return false;
+      }
+    }
+    matches!(
+      self.store.load_directory(result.output_directory).await,
+      Ok(Some(_))
+    )
+  }
+
+  fn fingerprint(req: &MultiPlatformProcess) -> Fingerprint {
+    // The request is already addressable via its constituent Digests (input_files, argv, env,
+    // etc): hash a stable debug rendering of it, rather than re-deriving each field by hand, so
+    // that this cache invalidates itself whenever `Process` grows a new field that affects
+    // execution.
+    Digest::of_bytes(format!("{:?}", req).as_bytes()).0
+  }
+
+  fn entry_path(&self, fingerprint: &Fingerprint) -> PathBuf {
+    self.cache_dir.join(fingerprint.to_hex())
+  }
+
+  async fn load(path: &Path) -> Option<CacheEntry> {
+    let bytes = tokio::fs::read(path).await.ok()?;
+    bincode::deserialize(&bytes).ok()
+  }
+
+  fn store(path: &Path, entry: &CacheEntry) -> Result<(), String> {
+    let bytes =
+      bincode::serialize(entry).map_err(|e| format!("Failed to serialize cache entry: {}", e))?;
+    let parent = path
+      .parent()
+      .ok_or_else(|| format!("{} has no parent directory", path.display()))?;
+    // Write to a sibling temp file with a unique (not key-derived) name and rename into place, so
+    // that a reader never observes a partially written entry, and two writers racing to fill the
+    // same key (e.g. a synchronous miss racing a background refresh of that same fingerprint)
+    // don't clobber each other's bytes before either rename.
+    let mut temp_file = tempfile::Builder::new()
+      .prefix(".tmp-")
+      .tempfile_in(parent)
+      .map_err(|e| format!("Failed to create temp file in {}: {}", parent.display(), e))?;
+    std::io::Write::write_all(&mut temp_file, &bytes)
+      .map_err(|e| format!("Failed to write cache entry {}: {}", path.display(), e))?;
+    temp_file
+      .persist(path)
+      .map_err(|e| format!("Failed to finalize cache entry {}: {}", path.display(), e))?;
+    Ok(())
+  }
+
+  fn now_millis_since_epoch() -> u64 {
+    SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_millis() as u64
+  }
+
+  /// Drops `write_instants` entries that have aged out of both the fresh and stale windows, so
+  /// that a long-running daemon process doesn't grow this map without bound across every distinct
+  /// fingerprint it's ever cached. Also best-effort-removes their on-disk entry, since nothing
+  /// else will ever look at (or overwrite) a fingerprint that's never requested again.
+  fn prune_expired_write_instants(&self) {
+    let max_age = self.ttl.stale.unwrap_or(self.ttl.fresh);
+    let mut write_instants = self.write_instants.lock().unwrap();
+    let expired: Vec<Fingerprint> = write_instants
+      .iter()
+      .filter(|(_, written_at)| written_at.elapsed() > max_age)
+      .map(|(fingerprint, _)| *fingerprint)
+      .collect();
+    for fingerprint in expired {
+      write_instants.remove(&fingerprint);
+      let _ = std::fs::remove_file(self.entry_path(&fingerprint));
+    }
+  }
+}
+
+#[async_trait]
+impl CommandRunnerTrait for CommandRunner {
+  async fn run(
+    &self,
+    context: Context,
+    workunit: &mut RunningWorkunit,
+    req: MultiPlatformProcess,
+  ) -> Result<FallibleProcessResultWithPlatform, String> {
+    tokio::fs::create_dir_all(&self.cache_dir)
+      .await
+      .map_err(|e| format!("Failed to create local cache dir: {}", e))?;
+
+    self.prune_expired_write_instants();
+
+    let fingerprint = Self::fingerprint(&req);
+    let path = self.entry_path(&fingerprint);
+
+    if let Some(entry) = Self::load(&path).await {
+      // This cache is not GC-aware (see the module-level doc comment), so an entry's digests may
+      // have been collected out from under it since it was written. Treat that the same as a
+      // cache miss -- falling through to a synchronous re-run below -- rather than handing back a
+      // result whose digests fail to load well after the fact in unrelated code.
+      if self.result_digests_exist(&entry.result).await {
+        let age = match self.write_instants.lock().unwrap().get(&fingerprint) {
+          Some(written_at) => written_at.elapsed(),
+          None => Duration::from_millis(
+            Self::now_millis_since_epoch().saturating_sub(entry.completed_at_millis_since_epoch),
+          ),
+        };
+        if age <= self.ttl.fresh {
+          return Ok(entry.result);
+        }
+        if let Some(stale) = self.ttl.stale {
+          if age <= stale {
+            // Return the stale-but-usable result immediately, and refresh it in the background so
+            // that the next request observes a fresh entry. The refresh outlives the request that
+            // triggered it, so it gets its own workunit (a child of the ambient store) rather than
+            // borrowing this request's `workunit` or standing up a throwaway test store.
+            //
+            // Only one refresh per fingerprint may be outstanding at a time: a concurrent (or
+            // closely-spaced) request for the same stale fingerprint would otherwise spawn its own
+            // redundant re-run before the first refresh's write lands.
+            if self.refreshing.lock().unwrap().insert(fingerprint) {
+              let inner = self.inner.clone();
+              let path = path.clone();
+              let write_instants = self.write_instants.clone();
+              let refreshing = self.refreshing.clone();
+              self.executor.spawn_and_ignore("local-cache-refresh", async move {
+                workunit_store::in_workunit!(
+                  "local_cache_refresh".to_owned(),
+                  Level::Debug,
+                  |workunit| async move {
+                    if let Ok(result) = inner.run(context, workunit, req).await {
+                      let entry = CacheEntry {
+                        completed_at_millis_since_epoch: CommandRunner::now_millis_since_epoch(),
+                        result,
+                      };
+                      if CommandRunner::store(&path, &entry).is_ok() {
+                        write_instants.lock().unwrap().insert(fingerprint, Instant::now());
+                      }
+                    }
+                    refreshing.lock().unwrap().remove(&fingerprint);
+                  }
+                )
+                .await;
+              });
+            }
+            return Ok(entry.result);
+          }
+        }
+      }
+    }
+
+    let result = self.inner.run(context, workunit, req).await?;
+    let entry = CacheEntry {
+      completed_at_millis_since_epoch: Self::now_millis_since_epoch(),
+      result: result.clone(),
+    };
+    Self::store(&path, &entry)?;
+    self
+      .write_instants
+      .lock()
+      .unwrap()
+      .insert(fingerprint, Instant::now());
+    Ok(result)
+  }
+}
@@ -0,0 +1,170 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use store::Store;
+use tempfile::TempDir;
+
+use crate::local_cache::{CacheTTL, CommandRunner as CachingCommandRunner};
+use crate::{
+  CommandRunner as CommandRunnerTrait, Context, FallibleProcessResultWithPlatform,
+  MultiPlatformProcess, Platform, Process,
+};
+use hashing::EMPTY_DIGEST;
+use testutil::owned_string_vec;
+use workunit_store::{RunningWorkunit, WorkunitStore};
+
+/// A stub inner `CommandRunner` that counts how many times it was actually invoked, so that
+/// tests can assert on cache hits vs misses without shelling out to a real process.
+struct CountingRunner {
+  runs: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl CommandRunnerTrait for CountingRunner {
+  async fn run(
+    &self,
+    _context: Context,
+    _workunit: &mut RunningWorkunit,
+    _req: MultiPlatformProcess,
+  ) -> Result<FallibleProcessResultWithPlatform, String> {
+    self.runs.fetch_add(1, Ordering::SeqCst);
+    Ok(FallibleProcessResultWithPlatform {
+      stdout_digest: EMPTY_DIGEST,
+      stderr_digest: EMPTY_DIGEST,
+      exit_code: 0,
+      output_directory: EMPTY_DIGEST,
+      platform: Platform::current().unwrap(),
+      metadata: Default::default(),
+    })
+  }
+}
+
+async fn run_once(
+  runner: &CachingCommandRunner,
+  workunit: &mut RunningWorkunit,
+) -> FallibleProcessResultWithPlatform {
+  let req: MultiPlatformProcess = Process::new(owned_string_vec(&["/bin/echo", "hello"])).into();
+  runner.run(Context::default(), workunit, req).await.unwrap()
+}
+
+#[tokio::test]
+async fn cache_hit_does_not_rerun_inner() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let runs = Arc::new(AtomicUsize::new(0));
+  let cache_dir = TempDir::new().unwrap();
+  let executor = task_executor::Executor::new();
+  let store_dir = TempDir::new().unwrap();
+  let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
+  let runner = CachingCommandRunner::new(
+    store,
+    Arc::new(CountingRunner { runs: runs.clone() }),
+    executor,
+    cache_dir.path().to_owned(),
+    CacheTTL {
+      fresh: Duration::from_secs(60),
+      stale: None,
+    },
+  );
+
+  run_once(&runner, &mut workunit).await;
+  run_once(&runner, &mut workunit).await;
+
+  assert_eq!(runs.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn expired_entry_triggers_rerun() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let runs = Arc::new(AtomicUsize::new(0));
+  let cache_dir = TempDir::new().unwrap();
+  let executor = task_executor::Executor::new();
+  let store_dir = TempDir::new().unwrap();
+  let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
+  let runner = CachingCommandRunner::new(
+    store,
+    Arc::new(CountingRunner { runs: runs.clone() }),
+    executor,
+    cache_dir.path().to_owned(),
+    CacheTTL {
+      fresh: Duration::from_millis(0),
+      stale: None,
+    },
+  );
+
+  run_once(&runner, &mut workunit).await;
+  run_once(&runner, &mut workunit).await;
+
+  assert_eq!(runs.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn stale_entry_is_served_while_refresh_happens_in_background() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let runs = Arc::new(AtomicUsize::new(0));
+  let cache_dir = TempDir::new().unwrap();
+  let executor = task_executor::Executor::new();
+  let store_dir = TempDir::new().unwrap();
+  let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
+  let runner = CachingCommandRunner::new(
+    store,
+    Arc::new(CountingRunner { runs: runs.clone() }),
+    executor,
+    cache_dir.path().to_owned(),
+    CacheTTL {
+      fresh: Duration::from_millis(0),
+      stale: Some(Duration::from_secs(60)),
+    },
+  );
+
+  run_once(&runner, &mut workunit).await;
+  run_once(&runner, &mut workunit).await;
+
+  // The second call should have returned the stale entry synchronously (i.e. without blocking on
+  // a second inner run), while the refresh runs in the background.
+  tokio::time::sleep(Duration::from_millis(100)).await;
+  assert_eq!(runs.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn expired_entries_are_pruned_from_disk() {
+  // Regression test: entries that have aged out of both the fresh and stale windows used to be
+  // kept around forever (both their in-memory `write_instants` entry and their on-disk file),
+  // growing without bound across every distinct fingerprint a long-running process ever cached.
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let runs = Arc::new(AtomicUsize::new(0));
+  let cache_dir = TempDir::new().unwrap();
+  let executor = task_executor::Executor::new();
+  let store_dir = TempDir::new().unwrap();
+  let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
+  let runner = CachingCommandRunner::new(
+    store,
+    Arc::new(CountingRunner { runs: runs.clone() }),
+    executor,
+    cache_dir.path().to_owned(),
+    CacheTTL {
+      fresh: Duration::from_millis(0),
+      stale: Some(Duration::from_millis(50)),
+    },
+  );
+
+  let req_a: MultiPlatformProcess = Process::new(owned_string_vec(&["/bin/echo", "a"])).into();
+  runner
+    .run(Context::default(), &mut workunit, req_a)
+    .await
+    .unwrap();
+  assert_eq!(std::fs::read_dir(cache_dir.path()).unwrap().count(), 1);
+
+  tokio::time::sleep(Duration::from_millis(100)).await;
+
+  // A second, distinct request triggers the runner's prune pass; by now the first entry has aged
+  // past both the fresh and stale windows, so it should be evicted rather than left behind.
+  let req_b: MultiPlatformProcess = Process::new(owned_string_vec(&["/bin/echo", "b"])).into();
+  runner
+    .run(Context::default(), &mut workunit, req_b)
+    .await
+    .unwrap();
+
+  assert_eq!(std::fs::read_dir(cache_dir.path()).unwrap().count(), 1);
+}
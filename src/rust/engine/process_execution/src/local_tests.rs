@@ -1,9 +1,10 @@
+use libc;
 use tempfile;
 use testutil;
 
 use crate::{
   CacheDest, CacheName, CommandRunner as CommandRunnerTrait, Context,
-  FallibleProcessResultWithPlatform, NamedCaches, Platform, Process, RelativePath,
+  FallibleProcessResultWithPlatform, NamedCaches, Platform, Process, PtySize, RelativePath,
 };
 use hashing::EMPTY_DIGEST;
 use shell_quote::bash;
@@ -121,6 +122,177 @@ async fn env_is_deterministic() {
   assert_eq!(result1.unwrap(), result2.unwrap());
 }
 
+#[tokio::test]
+#[cfg(unix)]
+async fn stdin_is_forwarded_to_child() {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+
+  let store_dir = TempDir::new().unwrap();
+  let executor = task_executor::Executor::new();
+  let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
+  store
+    .store_file_bytes(TestData::roland().bytes(), false)
+    .await
+    .expect("Error saving file bytes");
+
+  let work_dir = TempDir::new().unwrap();
+  let result = run_command_locally_in_dir(
+    Process::new(owned_string_vec(&["/bin/cat"])).stdin_digest(Some(TestData::roland().digest())),
+    work_dir.path().to_owned(),
+    true,
+    &mut workunit,
+    Some(store),
+    Some(executor),
+  )
+  .await
+  .unwrap();
+
+  assert_eq!(result.stdout_bytes, TestData::roland().bytes());
+  assert_eq!(result.stderr_bytes, "".as_bytes());
+  assert_eq!(result.original.exit_code, 0);
+  assert_eq!(result.original.output_directory, EMPTY_DIGEST);
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn large_stdin_does_not_deadlock_with_large_output() {
+  // Regression test: writing a large stdin payload to completion before draining output would
+  // deadlock against a child (like `cat`) that echoes its input to output as it reads: the
+  // child's stdout pipe fills up while we're still blocked writing the rest of stdin, and the
+  // child in turn stops reading stdin because it's blocked writing to that full stdout pipe.
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+
+  let store_dir = TempDir::new().unwrap();
+  let executor = task_executor::Executor::new();
+  let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
+
+  let stdin_bytes = "x".repeat(200_000).into_bytes();
+  let stdin_digest = store
+    .store_file_bytes(stdin_bytes.clone(), false)
+    .await
+    .expect("Error saving stdin bytes");
+
+  let process =
+    Process::new(owned_string_vec(&["/bin/cat"])).stdin_digest(Some(stdin_digest));
+
+  let work_dir = TempDir::new().unwrap();
+  let result = run_command_locally_in_dir(
+    process,
+    work_dir.path().to_owned(),
+    true,
+    &mut workunit,
+    Some(store),
+    Some(executor),
+  )
+  .await
+  .unwrap();
+
+  assert_eq!(result.stdout_bytes, stdin_bytes);
+  assert_eq!(result.stderr_bytes, "".as_bytes());
+  assert_eq!(result.original.exit_code, 0);
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn stdin_is_closed_when_absent() {
+  // `/bin/cat` reads until EOF; with no stdin_digest the child's stdin should be closed
+  // immediately, so it should exit having echoed nothing.
+  let result = run_command_locally(Process::new(owned_string_vec(&["/bin/cat"])))
+    .await
+    .unwrap();
+
+  assert_eq!(result.stdout_bytes, "".as_bytes());
+  assert_eq!(result.stderr_bytes, "".as_bytes());
+  assert_eq!(result.original.exit_code, 0);
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn pty_reports_isatty() {
+  let result = run_command_locally(
+    Process::new(owned_string_vec(&["/bin/bash", "-c", "test -t 1 && echo -n yes"]))
+      .pty(Some(PtySize { rows: 24, cols: 80 })),
+  )
+  .await
+  .unwrap();
+
+  assert_eq!(result.stdout_bytes, "yes".as_bytes());
+  assert_eq!(result.original.exit_code, 0);
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn stdin_is_forwarded_over_pty_and_reaches_eof() {
+  // Regression test: combining `pty` with `stdin_digest` used to hang `run()` forever, because
+  // dropping only the cloned write-side master fd never delivers an EOF to the pty (the slave
+  // only sees a hangup once *every* master-side fd is closed, and the fd used to read output
+  // stays open for the life of the run). `cat` blocks reading stdin until it sees EOF, so this
+  // would never complete without it. Echo is disabled first so the pty's own echo of the input
+  // doesn't get interleaved with cat's copy of it in the combined output stream.
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+
+  let store_dir = TempDir::new().unwrap();
+  let executor = task_executor::Executor::new();
+  let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
+  store
+    .store_file_bytes(TestData::roland().bytes(), false)
+    .await
+    .expect("Error saving file bytes");
+
+  let work_dir = TempDir::new().unwrap();
+  let result = run_command_locally_in_dir(
+    Process::new(owned_string_vec(&["/bin/bash", "-c", "stty -echo && cat"]))
+      .pty(Some(PtySize { rows: 24, cols: 80 }))
+      .stdin_digest(Some(TestData::roland().digest())),
+    work_dir.path().to_owned(),
+    true,
+    &mut workunit,
+    Some(store),
+    Some(executor),
+  )
+  .await
+  .unwrap();
+
+  assert_eq!(result.stdout_bytes, TestData::roland().bytes());
+  assert_eq!(result.original.exit_code, 0);
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn stdout_and_stderr_and_exit_code_without_pty_unaffected() {
+  // Regression test: requesting no pty should still take the plain-pipes path.
+  let result = run_command_locally(Process::new(owned_string_vec(&[
+    "/bin/bash",
+    "-c",
+    "echo -n foo ; echo >&2 -n bar ; exit 1",
+  ])))
+  .await
+  .unwrap();
+
+  assert_eq!(result.stdout_bytes, "foo".as_bytes());
+  assert_eq!(result.stderr_bytes, "bar".as_bytes());
+  assert_eq!(result.original.exit_code, 1);
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn large_interleaved_stdout_and_stderr_does_not_deadlock() {
+  // Writes enough to both stdout and stderr to fill the OS pipe buffers in both directions; if
+  // the two fds were drained sequentially instead of concurrently, this would hang rather than
+  // complete.
+  let result = run_command_locally(Process::new(owned_string_vec(&[
+    "/bin/bash",
+    "-c",
+    "for i in $(seq 1 100000); do echo -n o; echo >&2 -n e; done",
+  ])))
+  .await
+  .unwrap();
+
+  assert_eq!(result.stdout_bytes, "o".repeat(100000).as_bytes());
+  assert_eq!(result.stderr_bytes, "e".repeat(100000).as_bytes());
+  assert_eq!(result.original.exit_code, 0);
+}
+
 #[tokio::test]
 async fn binary_not_found() {
   let err_string = run_command_locally(Process::new(owned_string_vec(&["echo", "-n", "foo"])))
@@ -426,6 +598,69 @@ async fn test_directory_preservation() {
     .contains(quoted_command_line));
 }
 
+#[tokio::test]
+async fn test_directory_preservation_leaves_no_temp_files_behind() {
+  // Both the output files and __run.sh are materialized via a write-to-temp-then-rename, so a
+  // successful run should never leave a stray sibling temp file in the preserved workdir.
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+
+  let preserved_work_tmpdir = TempDir::new().unwrap();
+  let preserved_work_root = preserved_work_tmpdir.path().to_owned();
+
+  let store_dir = TempDir::new().unwrap();
+  let executor = task_executor::Executor::new();
+  let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
+
+  store
+    .store_file_bytes(TestData::roland().bytes(), false)
+    .await
+    .expect("Error saving file bytes");
+  store
+    .record_directory(&TestDirectory::containing_roland().directory(), true)
+    .await
+    .expect("Error saving directory");
+  store
+    .record_directory(&TestDirectory::nested().directory(), true)
+    .await
+    .expect("Error saving directory");
+
+  let cp = which("cp").expect("No cp on $PATH.");
+  let argv = vec![
+    find_bash(),
+    "-c".to_owned(),
+    format!("{} roland.ext ..", cp.display()),
+  ];
+
+  let mut process =
+    Process::new(argv).output_files(relative_paths(&["roland.ext"]).collect());
+  process.input_files = TestDirectory::nested().digest();
+  process.working_directory = Some(RelativePath::new("cats").unwrap());
+
+  run_command_locally_in_dir(
+    process,
+    preserved_work_root.clone(),
+    false,
+    &mut workunit,
+    Some(store),
+    Some(executor),
+  )
+  .await
+  .unwrap();
+
+  let subdirs = testutil::file::list_dir(&preserved_work_root);
+  assert_eq!(subdirs.len(), 1);
+  let run_dir = preserved_work_root.join(&subdirs[0]);
+  for entry in std::fs::read_dir(&run_dir).unwrap() {
+    let path = entry.unwrap().path();
+    let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+    assert!(
+      !file_name.starts_with(".tmp-"),
+      "Found leftover temp file: {}",
+      path.display()
+    );
+  }
+}
+
 #[tokio::test]
 async fn test_directory_preservation_error() {
   let (_, mut workunit) = WorkunitStore::setup_for_tests();
@@ -525,6 +760,84 @@ async fn timeout() {
   assert_that(&error_msg).contains("sleepy-cat");
 }
 
+#[tokio::test]
+#[cfg(unix)]
+async fn timeout_ignoring_sigterm_is_escalated_to_sigkill_after_grace_period() {
+  // Traps SIGTERM and ignores it, so that the runner is forced to escalate to SIGKILL once the
+  // grace period elapses; SIGKILL cannot be trapped, so the process's exit code reveals it.
+  let argv = vec![
+    find_bash(),
+    "-c".to_owned(),
+    "trap '' TERM; /bin/sleep 2".to_string(),
+  ];
+
+  let mut process = Process::new(argv);
+  process.timeout = Some(Duration::from_millis(100));
+  process.timeout_grace_period = Some(Duration::from_millis(200));
+  process.description = "stubborn-cat".to_string();
+
+  let result = run_command_locally(process).await.unwrap();
+
+  assert_eq!(result.original.exit_code, -9);
+  let error_msg = String::from_utf8(result.stdout_bytes.to_vec()).unwrap();
+  assert_that(&error_msg).contains("Exceeded timeout");
+  assert_that(&error_msg).contains("stubborn-cat");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn timeout_honoring_sigterm_exits_gracefully_within_grace_period() {
+  // A process that exits promptly on SIGTERM should never be escalated to SIGKILL. It exits via
+  // its own `exit 0` in response to the trap, so the exit code reflects that explicit exit call
+  // rather than being derived from a signal; it's `terminating_signal` that records which signal
+  // actually ended it.
+  let argv = vec![
+    find_bash(),
+    "-c".to_owned(),
+    "trap 'exit 0' TERM; /bin/sleep 2".to_string(),
+  ];
+
+  let mut process = Process::new(argv);
+  process.timeout = Some(Duration::from_millis(100));
+  process.timeout_grace_period = Some(Duration::from_secs(5));
+  process.description = "cooperative-cat".to_string();
+
+  let result = run_command_locally(process).await.unwrap();
+
+  assert_eq!(result.original.exit_code, 0);
+  assert_eq!(
+    result.original.metadata.terminating_signal,
+    Some(libc::SIGTERM)
+  );
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn timeout_signal_is_configurable() {
+  // Overrides the default SIGTERM with SIGUSR1; the process only exits gracefully via its trap
+  // (rather than being killed outright by an untrapped SIGTERM) if that overridden signal is
+  // actually what gets sent.
+  let argv = vec![
+    find_bash(),
+    "-c".to_owned(),
+    "trap 'exit 0' USR1; /bin/sleep 2".to_string(),
+  ];
+
+  let mut process = Process::new(argv);
+  process.timeout = Some(Duration::from_millis(100));
+  process.timeout_grace_period = Some(Duration::from_secs(5));
+  process.timeout_signal = libc::SIGUSR1;
+  process.description = "custom-signal-cat".to_string();
+
+  let result = run_command_locally(process).await.unwrap();
+
+  assert_eq!(result.original.exit_code, 0);
+  assert_eq!(
+    result.original.metadata.terminating_signal,
+    Some(libc::SIGUSR1)
+  );
+}
+
 #[tokio::test]
 async fn working_directory() {
   let (_, mut workunit) = WorkunitStore::setup_for_tests();